@@ -1,7 +1,144 @@
-use nalgebra::{SVector, SMatrix, DMatrix};
+use nalgebra::{SVector, SMatrix, DMatrix, DVector};
 use rand::Rng;
+use rand::seq::SliceRandom;
+use std::fs;
+use std::io::{self, Write};
 use super::activations::{Activation, ActivationType};
 
+/********** Cost *********/
+
+#[derive(Clone, Copy)]
+pub enum CostType {
+    SquaredError,
+    CrossEntropy,
+}
+
+pub struct Cost {
+    pub f: fn(&[f64], &[f64]) -> f64,
+    pub d: fn(f64, f64) -> f64,
+    /// `true` when `d` is already the full combined output-layer delta (as
+    /// with softmax + cross-entropy, which simplifies to `output - expected`),
+    /// so the output layer's own activation derivative must NOT be folded in
+    /// again on top of it.
+    pub combined_with_activation: bool,
+}
+
+impl Cost {
+    pub fn create(cost_type: CostType) -> Cost {
+        match cost_type {
+            CostType::SquaredError => Cost { f: squared_error, d: squared_error_d, combined_with_activation: false },
+            CostType::CrossEntropy => Cost { f: cross_entropy, d: cross_entropy_d, combined_with_activation: true },
+        }
+    }
+}
+
+fn squared_error(output: &[f64], expected: &[f64]) -> f64 {
+    output.iter().zip(expected.iter()).map(|(o, e)| (e - o).powi(2)).sum()
+}
+
+fn squared_error_d(output: f64, expected: f64) -> f64 {
+    2.0 * (output - expected)
+}
+
+fn cross_entropy(output: &[f64], expected: &[f64]) -> f64 {
+    -output.iter().zip(expected.iter()).map(|(o, e)| e * o.clamp(1e-12, 1.0).ln()).sum::<f64>()
+}
+
+fn cross_entropy_d(output: f64, expected: f64) -> f64 {
+    output - expected
+}
+
+/// `cost.combined_with_activation` assumes the output layer's activation
+/// cancels out in the cost derivative, which only holds for Softmax +
+/// CrossEntropy. Any other pairing would silently drop the real activation
+/// derivative, so refuse instead of guessing.
+fn assert_combined_cost_activation(cost: &Cost, activation: ActivationType) {
+    if cost.combined_with_activation && activation_tag(activation) != "Softmax" {
+        panic!(
+            "cost's combined_with_activation shortcut requires the output layer's activation to be Softmax, got `{}`",
+            activation_tag(activation)
+        );
+    }
+}
+
+/********** Optimizer *********/
+
+#[derive(Clone, Copy)]
+pub enum Optimizer {
+    Sgd,
+    Adam { beta1: f64, beta2: f64, epsilon: f64 },
+}
+
+impl Optimizer {
+    pub fn adam() -> Optimizer {
+        Optimizer::Adam { beta1: 0.9, beta2: 0.999, epsilon: 1e-8 }
+    }
+}
+
+/********** Shared per-parameter update math *********/
+//
+// `DenseLayer` (const-generic, `SMatrix`/`SVector`-backed) and
+// `DynamicDenseLayer` (`DMatrix`/`DVector`-backed) iterate their weights
+// differently, but apply the exact same per-scalar optimizer/regularization
+// formulas. Share that math here instead of hand-copying it into both
+// `apply_gradients` impls.
+
+/// In-place SGD step for one scalar parameter.
+fn sgd_step(weight: &mut f64, grad_sum: f64, batch_size: f64, l_rate: f64) {
+    *weight -= l_rate * (grad_sum / batch_size);
+}
+
+/// In-place Adam step for one scalar parameter, updating its bias-corrected
+/// moving averages `m`/`v` along the way.
+#[allow(clippy::too_many_arguments)]
+fn adam_step(
+    weight: &mut f64,
+    m: &mut f64,
+    v: &mut f64,
+    grad_sum: f64,
+    batch_size: f64,
+    l_rate: f64,
+    beta1: f64,
+    beta2: f64,
+    epsilon: f64,
+    t: i32,
+) {
+    let g = grad_sum / batch_size;
+    *m = beta1 * *m + (1.0 - beta1) * g;
+    *v = beta2 * *v + (1.0 - beta2) * g * g;
+    let m_hat = *m / (1.0 - beta1.powi(t));
+    let v_hat = *v / (1.0 - beta2.powi(t));
+    *weight -= l_rate * m_hat / (v_hat.sqrt() + epsilon);
+}
+
+/// In-place L2 weight decay for one scalar parameter.
+fn l2_decay_step(weight: &mut f64, l_rate: f64, lambda: f64) {
+    *weight -= l_rate * lambda * *weight;
+}
+
+/// Rescale a weight row in place so its L2 norm doesn't exceed `c`.
+fn max_norm_rescale<'a>(row: impl Iterator<Item = &'a mut f64>, c: f64) {
+    let mut row: Vec<&mut f64> = row.collect();
+    let row_norm = row.iter().map(|w| w.powi(2)).sum::<f64>().sqrt();
+    if row_norm > c {
+        let scale = c / row_norm;
+        for w in row.iter_mut() {
+            **w *= scale;
+        }
+    }
+}
+
+/// Reconstruct the next layer's weight matrix from the row-major
+/// `Vec<Vec<f64>>` returned by `get_weights()`, and propagate its deltas
+/// backward: `W^T * delta`.
+fn deltas_from_next_layer(nl_ws: Vec<Vec<f64>>, nl_deltas: Vec<f64>) -> DMatrix<f64> {
+    let w_format = (nl_ws.len(), nl_ws[0].len());
+    let flat: Vec<f64> = nl_ws.into_iter().flatten().collect();
+    let w_mat: DMatrix<f64> = DMatrix::from_row_slice(w_format.0, w_format.1, &flat);
+    let nl_deltas: DMatrix<f64> = DMatrix::from_vec(nl_deltas.len(), 1, nl_deltas);
+    w_mat.transpose() * nl_deltas
+}
+
 pub struct Neuron<const D: usize>{
     pub weights: SVector<f64, D>,
     pub bias: f64,
@@ -39,6 +176,7 @@ trait LayerFormat {
 
 pub trait NetLayer{
     fn foward(&self, inputs: Vec<f64>) -> Vec<f64>;
+    fn foward_batch(&self, inputs: &DMatrix<f64>) -> DMatrix<f64>;
     fn format(&self) -> (usize, usize);
 
     fn get_weights(&self) -> Vec<Vec<f64>>;
@@ -46,9 +184,19 @@ pub trait NetLayer{
     fn get_last_result(&self) -> Vec<f64>;
 
     fn foward_mut(&mut self, inputs: Vec<f64>) -> Vec<f64>;
-    fn backward_output(&mut self, expected: Vec<f64>) -> Vec<f64>;
+    fn backward_output(&mut self, expected: Vec<f64>, cost: &Cost) -> Vec<f64>;
     fn backward(&mut self, nl_ws: Vec<Vec<f64>>, nl_deltas: Vec<f64>) -> Vec<f64>;
-    fn update_layer(&mut self, pl_result: Vec<f64>, l_rate: f64);
+    fn accumulate_gradient(&mut self, pl_result: Vec<f64>);
+    fn apply_gradients(&mut self, batch_size: usize, l_rate: f64, optimizer: &Optimizer, t: usize);
+
+    fn get_bias(&self) -> Vec<f64>;
+    fn describe(&self) -> LayerKind;
+}
+
+/// What's needed to persist and rebuild a layer; see `ArtificialNetwork::save`/`load`.
+pub enum LayerKind {
+    Dense { activation: ActivationType },
+    Dropout { keep_prob: f64 },
 }
 
 pub struct DenseLayer<const IN_FMT: usize, const OUT_FMT: usize> {
@@ -57,8 +205,20 @@ pub struct DenseLayer<const IN_FMT: usize, const OUT_FMT: usize> {
     bias_vec: SVector<f64, OUT_FMT>,
 
     activation: Activation,
+    activation_type: ActivationType,
     last_result: SVector<f64, OUT_FMT>,
-    error: SVector<f64, OUT_FMT>
+    error: SVector<f64, OUT_FMT>,
+
+    m: SMatrix<f64, OUT_FMT, IN_FMT>,
+    v: SMatrix<f64, OUT_FMT, IN_FMT>,
+    bias_m: SVector<f64, OUT_FMT>,
+    bias_v: SVector<f64, OUT_FMT>,
+
+    grad_accum: SMatrix<f64, OUT_FMT, IN_FMT>,
+    bias_grad_accum: SVector<f64, OUT_FMT>,
+
+    l2_lambda: f64,
+    max_norm: Option<f64>,
 }
 
 impl<const IN_FMT:usize, const OUT_FMT:usize> DenseLayer<IN_FMT, OUT_FMT>{
@@ -72,16 +232,45 @@ impl<const IN_FMT:usize, const OUT_FMT:usize> DenseLayer<IN_FMT, OUT_FMT>{
             weights_mat: SMatrix::<f64, OUT_FMT, IN_FMT>::zeros(),
             bias_vec: SVector::<f64, OUT_FMT>::zeros(),
             activation: Activation::create(ActivationType::Default),
+            activation_type: ActivationType::Default,
 
             last_result: SVector::<f64, OUT_FMT>::zeros(),
-            error: SVector::zeros()
+            error: SVector::zeros(),
+
+            m: SMatrix::<f64, OUT_FMT, IN_FMT>::zeros(),
+            v: SMatrix::<f64, OUT_FMT, IN_FMT>::zeros(),
+            bias_m: SVector::<f64, OUT_FMT>::zeros(),
+            bias_v: SVector::<f64, OUT_FMT>::zeros(),
+
+            grad_accum: SMatrix::<f64, OUT_FMT, IN_FMT>::zeros(),
+            bias_grad_accum: SVector::<f64, OUT_FMT>::zeros(),
+
+            l2_lambda: 0.0,
+            max_norm: None,
         }
     }
 
     pub fn set_activation(&mut self, activation: ActivationType) {
+        self.activation_type = activation;
         self.activation = Activation::create(activation);
     }
 
+    pub fn load_weights(&mut self, weights: Vec<Vec<f64>>, bias: Vec<f64>) {
+        for (i, row) in weights.into_iter().enumerate() {
+            self.neurons[i].weights = SVector::from_vec(row);
+        }
+        self.update_weights_mat();
+        self.bias_vec = SVector::from_vec(bias);
+    }
+
+    pub fn set_l2(&mut self, lambda: f64) {
+        self.l2_lambda = lambda;
+    }
+
+    pub fn set_max_norm(&mut self, c: f64) {
+        self.max_norm = Some(c);
+    }
+
     pub fn randomize(&mut self) {
         for n in &mut self.neurons {
             n.randomize();
@@ -120,6 +309,17 @@ impl<const I:usize, const O:usize> NetLayer for DenseLayer<I,O> {
         self.last_result.data.0[0].to_vec()
     }
 
+    fn foward_batch(&self, inputs: &DMatrix<f64>) -> DMatrix<f64> {
+        let w = DMatrix::from_column_slice(O, I, self.weights_mat.as_slice());
+        let bias = DVector::from_column_slice(self.bias_vec.as_slice());
+
+        let mut out = w * inputs;
+        for mut col in out.column_iter_mut() {
+            col += &bias;
+        }
+        out.map(|o| (self.activation.f)(&o))
+    }
+
     fn format(&self) -> (usize, usize) {
         (I, O)
     }
@@ -136,66 +336,438 @@ impl<const I:usize, const O:usize> NetLayer for DenseLayer<I,O> {
         self.last_result.data.0[0].to_vec()
     }
 
-    fn backward_output(&mut self, expected: Vec<f64>) -> Vec<f64> {
-        let expected_vec =  SVector::from_vec(expected);
-        
-        self.error = expected_vec - self.last_result;
-        self.error = self.error.component_mul(&self.error); // (expected - output)^2
+    fn backward_output(&mut self, expected: Vec<f64>, cost: &Cost) -> Vec<f64> {
+        assert_combined_cost_activation(cost, self.activation_type);
 
-        let derivatives = self.last_result.map(|o| (self.activation.d)(&o));
-        self.error = self.error.component_mul(&derivatives); // (expected - output)^2 * derivative(output)
+        let output = self.last_result.data.0[0].to_vec();
+
+        let cost_derivative: SVector<f64, O> = SVector::from_iterator(
+            output.iter().zip(expected.iter()).map(|(o, e)| (cost.d)(*o, *e))
+        );
+
+        self.error = if cost.combined_with_activation {
+            cost_derivative
+        } else {
+            let derivatives = self.last_result.map(|o| (self.activation.d)(&o));
+            cost_derivative.component_mul(&derivatives)
+        };
 
         return self.error.data.0[0].to_vec();
     }
 
     fn backward(&mut self, nl_ws: Vec<Vec<f64>>, nl_deltas: Vec<f64>) -> Vec<f64> {
-        let derivatives = self.last_result.map(|o| (self.activation.d)(&o)); 
-        
-        let w_format = (nl_ws.len(), nl_ws[0].len());
-        let w_mat:DMatrix<f64> = DMatrix::from_vec(w_format.0, w_format.1, nl_ws.into_iter().flatten().collect());
-        
-        let nl_deltas:DMatrix<f64> = DMatrix::from_vec(nl_deltas.len(), 1, nl_deltas);
-
-        let e = w_mat.transpose() * nl_deltas;
+        let derivatives = self.last_result.map(|o| (self.activation.d)(&o));
+        let e = deltas_from_next_layer(nl_ws, nl_deltas);
         self.error = e.component_mul(&derivatives);
-        
+
         return self.error.data.0[0].to_vec();
     }
 
-    fn update_layer(&mut self, pl_result: Vec<f64>, l_rate: f64) {
+    fn accumulate_gradient(&mut self, pl_result: Vec<f64>) {
         for (i, n) in self.neurons.iter().enumerate() {
             for (j, _) in n.weights.iter().enumerate() {
-                self.weights_mat[(i,j)] -= l_rate * self.error[i] * pl_result[j];
+                self.grad_accum[(i, j)] += self.error[i] * pl_result[j];
             }
-            self.bias_vec[i] -= l_rate * self.error[i];
+            self.bias_grad_accum[i] += self.error[i];
         }
     }
 
+    fn apply_gradients(&mut self, batch_size: usize, l_rate: f64, optimizer: &Optimizer, t: usize) {
+        let batch_size = batch_size as f64;
+
+        match *optimizer {
+            Optimizer::Sgd => {
+                for (i, n) in self.neurons.iter().enumerate() {
+                    for (j, _) in n.weights.iter().enumerate() {
+                        sgd_step(&mut self.weights_mat[(i, j)], self.grad_accum[(i, j)], batch_size, l_rate);
+                    }
+                    sgd_step(&mut self.bias_vec[i], self.bias_grad_accum[i], batch_size, l_rate);
+                }
+            }
+            Optimizer::Adam { beta1, beta2, epsilon } => {
+                let t = t as i32;
+                for (i, n) in self.neurons.iter().enumerate() {
+                    for (j, _) in n.weights.iter().enumerate() {
+                        adam_step(&mut self.weights_mat[(i, j)], &mut self.m[(i, j)], &mut self.v[(i, j)], self.grad_accum[(i, j)], batch_size, l_rate, beta1, beta2, epsilon, t);
+                    }
+                    adam_step(&mut self.bias_vec[i], &mut self.bias_m[i], &mut self.bias_v[i], self.bias_grad_accum[i], batch_size, l_rate, beta1, beta2, epsilon, t);
+                }
+            }
+        }
+
+        if self.l2_lambda != 0.0 {
+            for i in 0..O {
+                for j in 0..I {
+                    l2_decay_step(&mut self.weights_mat[(i, j)], l_rate, self.l2_lambda);
+                }
+            }
+        }
+
+        if let Some(c) = self.max_norm {
+            for i in 0..O {
+                max_norm_rescale(self.weights_mat.row_mut(i).iter_mut(), c);
+            }
+        }
+
+        // keep the per-neuron weights (read by get_weights, and so by
+        // backward and save) in step with the updated weights_mat
+        for (i, n) in self.neurons.iter_mut().enumerate() {
+            for (j, w) in n.weights.iter_mut().enumerate() {
+                *w = self.weights_mat[(i, j)];
+            }
+        }
+
+        self.grad_accum = SMatrix::zeros();
+        self.bias_grad_accum = SVector::zeros();
+    }
+
     fn get_errors(&self) -> Vec<f64> {
         self.error.data.0[0].to_vec()
     }
+
+    fn get_bias(&self) -> Vec<f64> {
+        self.bias_vec.data.0[0].to_vec()
+    }
+
+    fn describe(&self) -> LayerKind {
+        LayerKind::Dense { activation: self.activation_type }
+    }
+}
+
+/********** Dropout *********/
+
+pub struct DropoutLayer {
+    size: usize,
+    keep_prob: f64,
+    mask: Vec<f64>,
+    last_result: Vec<f64>,
+    error: Vec<f64>,
+}
+
+impl DropoutLayer {
+    pub fn new(size: usize, keep_prob: f64) -> DropoutLayer {
+        DropoutLayer {
+            size,
+            keep_prob,
+            mask: vec![1.0; size],
+            last_result: vec![0.0; size],
+            error: vec![0.0; size],
+        }
+    }
+}
+
+impl NetLayer for DropoutLayer {
+    fn foward(&self, inputs: Vec<f64>) -> Vec<f64> {
+        inputs
+    }
+
+    fn foward_batch(&self, inputs: &DMatrix<f64>) -> DMatrix<f64> {
+        inputs.clone()
+    }
+
+    fn foward_mut(&mut self, inputs: Vec<f64>) -> Vec<f64> {
+        let mut rng = rand::thread_rng();
+        self.mask = inputs.iter()
+            .map(|_| if rng.gen::<f64>() < self.keep_prob { 1.0 / self.keep_prob } else { 0.0 })
+            .collect();
+        self.last_result = inputs.iter().zip(self.mask.iter()).map(|(i, m)| i * m).collect();
+        self.last_result.clone()
+    }
+
+    fn format(&self) -> (usize, usize) {
+        (self.size, self.size)
+    }
+
+    fn get_weights(&self) -> Vec<Vec<f64>> {
+        (0..self.size)
+            .map(|i| (0..self.size).map(|j| if i == j { self.mask[i] } else { 0.0 }).collect())
+            .collect()
+    }
+
+    fn get_errors(&self) -> Vec<f64> {
+        self.error.clone()
+    }
+
+    fn get_last_result(&self) -> Vec<f64> {
+        self.last_result.clone()
+    }
+
+    fn backward_output(&mut self, _expected: Vec<f64>, _cost: &Cost) -> Vec<f64> {
+        panic!("DropoutLayer cannot be used as the output layer");
+    }
+
+    fn backward(&mut self, nl_ws: Vec<Vec<f64>>, nl_deltas: Vec<f64>) -> Vec<f64> {
+        // dropout has no activation of its own (derivative 1); the mask is
+        // folded into get_weights(), which the caller passes as nl_ws to the
+        // layer before this one, so it is applied there
+        self.error = deltas_from_next_layer(nl_ws, nl_deltas).as_slice().to_vec();
+        self.error.clone()
+    }
+
+    fn accumulate_gradient(&mut self, _pl_result: Vec<f64>) {}
+
+    fn apply_gradients(&mut self, _batch_size: usize, _l_rate: f64, _optimizer: &Optimizer, _t: usize) {}
+
+    fn get_bias(&self) -> Vec<f64> {
+        vec![0.0; self.size]
+    }
+
+    fn describe(&self) -> LayerKind {
+        LayerKind::Dropout { keep_prob: self.keep_prob }
+    }
 }
 
+/********** Dynamic dense layer *********/
+
+/// A `DenseLayer` sibling backed by runtime-sized `DMatrix`/`DVector` instead
+/// of `IN_FMT`/`OUT_FMT` const generics. `DenseLayer<IN, OUT>` is monomorphized
+/// per shape at compile time, so `ArtificialNetwork::load` (which only knows
+/// shapes at runtime, from whatever was written by `save`) can't instantiate
+/// it for an arbitrary saved shape. This layer has the same forward/backward
+/// behavior but can be built for any `(in, out)` pair, so `load` can
+/// reconstruct any previously saved network rather than only a fixed set of
+/// pre-registered shapes.
+pub struct DynamicDenseLayer {
+    in_fmt: usize,
+    out_fmt: usize,
+    weights_mat: DMatrix<f64>,
+    bias_vec: DVector<f64>,
+
+    activation: Activation,
+    activation_type: ActivationType,
+    last_result: DVector<f64>,
+    error: DVector<f64>,
+
+    m: DMatrix<f64>,
+    v: DMatrix<f64>,
+    bias_m: DVector<f64>,
+    bias_v: DVector<f64>,
+
+    grad_accum: DMatrix<f64>,
+    bias_grad_accum: DVector<f64>,
+
+    l2_lambda: f64,
+    max_norm: Option<f64>,
+}
+
+impl DynamicDenseLayer {
+    pub fn new(in_fmt: usize, out_fmt: usize) -> DynamicDenseLayer {
+        DynamicDenseLayer {
+            in_fmt,
+            out_fmt,
+            weights_mat: DMatrix::zeros(out_fmt, in_fmt),
+            bias_vec: DVector::zeros(out_fmt),
+
+            activation: Activation::create(ActivationType::Default),
+            activation_type: ActivationType::Default,
+            last_result: DVector::zeros(out_fmt),
+            error: DVector::zeros(out_fmt),
+
+            m: DMatrix::zeros(out_fmt, in_fmt),
+            v: DMatrix::zeros(out_fmt, in_fmt),
+            bias_m: DVector::zeros(out_fmt),
+            bias_v: DVector::zeros(out_fmt),
+
+            grad_accum: DMatrix::zeros(out_fmt, in_fmt),
+            bias_grad_accum: DVector::zeros(out_fmt),
+
+            l2_lambda: 0.0,
+            max_norm: None,
+        }
+    }
+
+    pub fn set_activation(&mut self, activation: ActivationType) {
+        self.activation_type = activation;
+        self.activation = Activation::create(activation);
+    }
+
+    pub fn load_weights(&mut self, weights: Vec<Vec<f64>>, bias: Vec<f64>) {
+        for (i, row) in weights.into_iter().enumerate() {
+            for (j, w) in row.into_iter().enumerate() {
+                self.weights_mat[(i, j)] = w;
+            }
+        }
+        self.bias_vec = DVector::from_vec(bias);
+    }
+}
+
+impl NetLayer for DynamicDenseLayer {
+    fn foward(&self, inputs: Vec<f64>) -> Vec<f64> {
+        let input_vec = DVector::from_vec(inputs);
+        let out = &self.weights_mat * input_vec + &self.bias_vec;
+        out.iter().map(|o| (self.activation.f)(o)).collect()
+    }
+
+    fn foward_batch(&self, inputs: &DMatrix<f64>) -> DMatrix<f64> {
+        let mut out = &self.weights_mat * inputs;
+        for mut col in out.column_iter_mut() {
+            col += &self.bias_vec;
+        }
+        out.map(|o| (self.activation.f)(&o))
+    }
+
+    fn foward_mut(&mut self, inputs: Vec<f64>) -> Vec<f64> {
+        let res = self.foward(inputs);
+        self.last_result = DVector::from_vec(res);
+        self.last_result.as_slice().to_vec()
+    }
+
+    fn format(&self) -> (usize, usize) {
+        (self.in_fmt, self.out_fmt)
+    }
+
+    fn get_weights(&self) -> Vec<Vec<f64>> {
+        (0..self.out_fmt)
+            .map(|i| (0..self.in_fmt).map(|j| self.weights_mat[(i, j)]).collect())
+            .collect()
+    }
+
+    fn get_last_result(&self) -> Vec<f64> {
+        self.last_result.as_slice().to_vec()
+    }
+
+    fn backward_output(&mut self, expected: Vec<f64>, cost: &Cost) -> Vec<f64> {
+        assert_combined_cost_activation(cost, self.activation_type);
+
+        let output = self.last_result.as_slice().to_vec();
+        let cost_derivative = DVector::from_iterator(
+            self.out_fmt,
+            output.iter().zip(expected.iter()).map(|(o, e)| (cost.d)(*o, *e)),
+        );
+
+        self.error = if cost.combined_with_activation {
+            cost_derivative
+        } else {
+            let derivatives = self.last_result.map(|o| (self.activation.d)(&o));
+            cost_derivative.component_mul(&derivatives)
+        };
+
+        self.error.as_slice().to_vec()
+    }
+
+    fn backward(&mut self, nl_ws: Vec<Vec<f64>>, nl_deltas: Vec<f64>) -> Vec<f64> {
+        let derivatives = self.last_result.map(|o| (self.activation.d)(&o));
+
+        let e = deltas_from_next_layer(nl_ws, nl_deltas);
+        self.error = DVector::from_column_slice(e.as_slice()).component_mul(&derivatives);
+
+        self.error.as_slice().to_vec()
+    }
+
+    fn accumulate_gradient(&mut self, pl_result: Vec<f64>) {
+        for i in 0..self.out_fmt {
+            for (j, pl_r) in pl_result.iter().enumerate().take(self.in_fmt) {
+                self.grad_accum[(i, j)] += self.error[i] * pl_r;
+            }
+            self.bias_grad_accum[i] += self.error[i];
+        }
+    }
+
+    fn apply_gradients(&mut self, batch_size: usize, l_rate: f64, optimizer: &Optimizer, t: usize) {
+        let batch_size = batch_size as f64;
+
+        match *optimizer {
+            Optimizer::Sgd => {
+                for i in 0..self.out_fmt {
+                    for j in 0..self.in_fmt {
+                        sgd_step(&mut self.weights_mat[(i, j)], self.grad_accum[(i, j)], batch_size, l_rate);
+                    }
+                    sgd_step(&mut self.bias_vec[i], self.bias_grad_accum[i], batch_size, l_rate);
+                }
+            }
+            Optimizer::Adam { beta1, beta2, epsilon } => {
+                let t = t as i32;
+                for i in 0..self.out_fmt {
+                    for j in 0..self.in_fmt {
+                        adam_step(&mut self.weights_mat[(i, j)], &mut self.m[(i, j)], &mut self.v[(i, j)], self.grad_accum[(i, j)], batch_size, l_rate, beta1, beta2, epsilon, t);
+                    }
+                    adam_step(&mut self.bias_vec[i], &mut self.bias_m[i], &mut self.bias_v[i], self.bias_grad_accum[i], batch_size, l_rate, beta1, beta2, epsilon, t);
+                }
+            }
+        }
+
+        if self.l2_lambda != 0.0 {
+            for i in 0..self.out_fmt {
+                for j in 0..self.in_fmt {
+                    l2_decay_step(&mut self.weights_mat[(i, j)], l_rate, self.l2_lambda);
+                }
+            }
+        }
+
+        if let Some(c) = self.max_norm {
+            for i in 0..self.out_fmt {
+                max_norm_rescale(self.weights_mat.row_mut(i).iter_mut(), c);
+            }
+        }
+
+        self.grad_accum = DMatrix::zeros(self.out_fmt, self.in_fmt);
+        self.bias_grad_accum = DVector::zeros(self.out_fmt);
+    }
+
+    fn get_errors(&self) -> Vec<f64> {
+        self.error.as_slice().to_vec()
+    }
+
+    fn get_bias(&self) -> Vec<f64> {
+        self.bias_vec.as_slice().to_vec()
+    }
+
+    fn describe(&self) -> LayerKind {
+        LayerKind::Dense { activation: self.activation_type }
+    }
+}
 
 /********** Network *********/
 
 pub struct ArtificialNetwork {
-    layers: Vec<Box<dyn NetLayer>>
+    layers: Vec<Box<dyn NetLayer>>,
+    cost: Cost,
+    optimizer: Optimizer,
+    step: usize,
+
+    on_epoch: Option<Box<dyn FnMut(usize, f64)>>,
+    on_error: Option<Box<dyn FnMut(f64)>>,
 }
 
 impl ArtificialNetwork {
     pub fn new() -> ArtificialNetwork {
         ArtificialNetwork {
             layers: Vec::new(),
+            cost: Cost::create(CostType::SquaredError),
+            optimizer: Optimizer::Sgd,
+            step: 0,
+
+            on_epoch: None,
+            on_error: None,
         }
     }
 
+    pub fn on_epoch<F: FnMut(usize, f64) + 'static>(&mut self, callback: F) -> &mut Self {
+        self.on_epoch = Some(Box::new(callback));
+        self
+    }
+
+    pub fn on_error<F: FnMut(f64) + 'static>(&mut self, callback: F) -> &mut Self {
+        self.on_error = Some(Box::new(callback));
+        self
+    }
+
     pub fn add_layer(&mut self, layer: Box<dyn NetLayer>) -> &mut Self {
         self.verify_new_layer(&layer);
         self.layers.push(layer);
         self
     }
 
+    pub fn set_cost(&mut self, cost_type: CostType) -> &mut Self {
+        self.cost = Cost::create(cost_type);
+        self
+    }
+
+    pub fn set_optimizer(&mut self, optimizer: Optimizer) -> &mut Self {
+        self.optimizer = optimizer;
+        self
+    }
+
     
     pub fn foward(&self, inputs: Vec<f64>) -> Vec<f64> {
         let mut inputs = inputs;
@@ -205,17 +777,60 @@ impl ArtificialNetwork {
         inputs
     }
 
-    pub fn train(&mut self, inputs: Vec<Vec<f64>>, expected: Vec<Vec<f64>>, l_rate: f64, epochs: usize) -> (f64, f64) {
-        let loss1 = self.learn(inputs[0].clone(), expected[0].clone(), l_rate);
-        let mut loss2 = 0.;
+    /// Forwards a whole dataset in one shot: N inputs are stacked into a
+    /// single `DMatrix` and each layer runs one matrix-matrix multiply
+    /// instead of N matrix-vector ones.
+    pub fn foward_batch(&self, inputs: Vec<Vec<f64>>) -> Vec<Vec<f64>> {
+        let n = inputs.len();
+        let in_fmt = self.layers[0].format().0;
+
+        let flat: Vec<f64> = (0..n)
+            .flat_map(|s| (0..in_fmt).map(|i| inputs[s][i]).collect::<Vec<_>>())
+            .collect();
+        let mut batch = DMatrix::from_vec(in_fmt, n, flat);
+
+        for layer in &self.layers {
+            batch = layer.foward_batch(&batch);
+        }
+
+        let out_fmt = batch.nrows();
+        (0..n).map(|s| (0..out_fmt).map(|i| batch[(i, s)]).collect()).collect()
+    }
+
+    pub fn train(&mut self, inputs: Vec<Vec<f64>>, expected: Vec<Vec<f64>>, l_rate: f64, epochs: usize, batch_size: usize, shuffle: bool) -> f64 {
+        if batch_size == 0 {
+            panic!("batch_size must be greater than 0");
+        }
+
         let train_data_size = inputs.len();
-        for i in 0..epochs {
-            
-            loss2 = self.learn(inputs[i % train_data_size].clone(), expected[i % train_data_size].clone(), l_rate);
-            print!("\rEpoch: {} \t\t| loss: {:?}", i, loss2);
+        let mut indices: Vec<usize> = (0..train_data_size).collect();
+        let mut rng = rand::thread_rng();
+
+        let mut epoch_loss = 0.;
+        for epoch in 0..epochs {
+            if shuffle {
+                indices.shuffle(&mut rng);
+            }
+
+            epoch_loss = 0.;
+            for batch in indices.chunks(batch_size) {
+                for &i in batch {
+                    let loss = self.learn(inputs[i].clone(), expected[i].clone());
+                    epoch_loss += loss;
+                    if let Some(on_error) = &mut self.on_error {
+                        on_error(loss);
+                    }
+                }
+                self.apply_gradients(batch.len(), l_rate);
+            }
+            epoch_loss /= train_data_size as f64;
+
+            if let Some(on_epoch) = &mut self.on_epoch {
+                on_epoch(epoch, epoch_loss);
+            }
         }
-        println!();
-        return (loss1, loss2);
+
+        return epoch_loss;
     }
 
     fn foward_mut(&mut self, inputs: Vec<f64>) -> Vec<f64> {
@@ -240,7 +855,7 @@ impl ArtificialNetwork {
         let size = self.layers.len();
 
         let mut expected = expected;
-        expected = self.layers[size - 1].backward_output(expected);
+        expected = self.layers[size - 1].backward_output(expected, &self.cost);
 
         let mut ll_ws = self.layers[size - 1].get_weights();
 
@@ -250,30 +865,356 @@ impl ArtificialNetwork {
         }
     }
 
-    fn learn(&mut self, inputs:Vec<f64>, expected: Vec<f64>, l_rate: f64) -> f64{
+    fn learn(&mut self, inputs:Vec<f64>, expected: Vec<f64>) -> f64{
         let inp_clone = inputs.clone();
         let exp_clone = expected.clone();
 
         self.foward_mut(inputs);
         self.backward(expected);
-        
+
         for i in &mut (1..self.layers.len()) {
             let previous_layer_result = self.layers[i-1].get_last_result();
-            self.layers[i].update_layer(previous_layer_result, l_rate);
+            self.layers[i].accumulate_gradient(previous_layer_result);
         }
-    
+
         return self.get_loss(inp_clone, exp_clone);
     }
 
+    fn apply_gradients(&mut self, batch_size: usize, l_rate: f64) {
+        self.step += 1;
+        for layer in &mut self.layers {
+            layer.apply_gradients(batch_size, l_rate, &self.optimizer, self.step);
+        }
+    }
+
     fn get_loss(&self, input: Vec<f64>, expected: Vec<f64>) -> f64 {
-        let output_format = self.layers[self.layers.len() - 1].format();
         let out = self.foward(input);
+        (self.cost.f)(&out, &expected)
+    }
+
+    /// Persist every layer's format, activation, weights and bias to a compact
+    /// text file so a trained network can be reused without retraining.
+    pub fn save(&self, path: &str) -> io::Result<()> {
+        let mut out = String::new();
+        out.push_str(&format!("{}\n", self.layers.len()));
+
+        for layer in &self.layers {
+            let (in_fmt, out_fmt) = layer.format();
+            match layer.describe() {
+                LayerKind::Dense { activation } => {
+                    out.push_str(&format!("DENSE {} {} {}\n", in_fmt, out_fmt, activation_tag(activation)));
+                    out.push_str(&vec_to_line(&layer.get_bias()));
+                    out.push('\n');
+                    for row in layer.get_weights() {
+                        out.push_str(&vec_to_line(&row));
+                        out.push('\n');
+                    }
+                }
+                LayerKind::Dropout { keep_prob } => {
+                    out.push_str(&format!("DROPOUT {} {}\n", in_fmt, keep_prob));
+                }
+            }
+        }
+
+        let mut file = fs::File::create(path)?;
+        file.write_all(out.as_bytes())
+    }
 
-        let expct = DMatrix::from_vec(output_format.1, 1, expected);
-        let out = DMatrix::from_vec(output_format.1, 1, out);
-        
-        let sub = out - expct;  
-        let loss = sub.component_mul(&sub); // (expected - output)^2
-        return loss.sum();
+    /// Rebuild a network previously written by `save`. `DenseLayer` is
+    /// const-generic over its shape, so layers are reconstructed as
+    /// `DynamicDenseLayer`, which can represent any `(in, out)` pair at
+    /// runtime instead of only the shapes a particular build happened to
+    /// monomorphize.
+    pub fn load(path: &str) -> io::Result<ArtificialNetwork> {
+        let contents = fs::read_to_string(path)?;
+        let mut lines = contents.lines();
+
+        let layer_count: usize = lines.next()
+            .and_then(|l| l.trim().parse().ok())
+            .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing layer count"))?;
+
+        let mut network = ArtificialNetwork::new();
+        for _ in 0..layer_count {
+            let header = lines.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "truncated file"))?;
+            let mut parts = header.split_whitespace();
+
+            match parts.next() {
+                Some("DENSE") => {
+                    let in_fmt: usize = parts.next().and_then(|p| p.parse().ok())
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad DENSE header"))?;
+                    let out_fmt: usize = parts.next().and_then(|p| p.parse().ok())
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad DENSE header"))?;
+                    let activation = activation_from_tag(parts.next().unwrap_or(""))?;
+
+                    let bias = line_to_vec(lines.next().ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing bias line"))?);
+                    let weights: Vec<Vec<f64>> = (0..out_fmt)
+                        .map(|_| lines.next().map(line_to_vec).ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "missing weight row")))
+                        .collect::<io::Result<_>>()?;
+
+                    let mut layer = DynamicDenseLayer::new(in_fmt, out_fmt);
+                    layer.set_activation(activation);
+                    layer.load_weights(weights, bias);
+                    network.add_layer(Box::new(layer));
+                }
+                Some("DROPOUT") => {
+                    let size: usize = parts.next().and_then(|p| p.parse().ok())
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad DROPOUT header"))?;
+                    let keep_prob: f64 = parts.next().and_then(|p| p.parse().ok())
+                        .ok_or_else(|| io::Error::new(io::ErrorKind::InvalidData, "bad DROPOUT header"))?;
+
+                    network.add_layer(Box::new(DropoutLayer::new(size, keep_prob)));
+                }
+                other => return Err(io::Error::new(io::ErrorKind::InvalidData, format!("unknown layer kind `{:?}`", other))),
+            }
+        }
+
+        Ok(network)
+    }
+}
+
+fn vec_to_line(values: &[f64]) -> String {
+    values.iter().map(|v| v.to_string()).collect::<Vec<_>>().join(" ")
+}
+
+fn line_to_vec(line: &str) -> Vec<f64> {
+    line.split_whitespace().map(|v| v.parse().unwrap_or(0.0)).collect()
+}
+
+fn activation_tag(activation: ActivationType) -> String {
+    format!("{:?}", activation)
+}
+
+/// Covers every `ActivationType` variant used elsewhere in this module;
+/// extend alongside `activation_tag` whenever a new one is added.
+fn activation_from_tag(tag: &str) -> io::Result<ActivationType> {
+    match tag {
+        "Default" => Ok(ActivationType::Default),
+        "Softmax" => Ok(ActivationType::Softmax),
+        other => Err(io::Error::new(
+            io::ErrorKind::InvalidData,
+            format!("unknown activation tag `{}`; extend activation_from_tag to support it", other),
+        )),
+    }
+}
+
+#[cfg(test)]
+mod foward_batch_tests {
+    use super::*;
+
+    #[test]
+    fn foward_batch_matches_per_sample_foward() {
+        let mut network = ArtificialNetwork::new();
+        let mut layer = DenseLayer::<3, 2>::new();
+        layer.randomize();
+        network.add_layer(Box::new(layer));
+
+        let inputs = vec![
+            vec![1.0, 2.0, 3.0],
+            vec![10.0, 20.0, 30.0],
+            vec![-1.0, 0.5, 4.0],
+        ];
+
+        let batched = network.foward_batch(inputs.clone());
+        let per_sample: Vec<Vec<f64>> = inputs.into_iter().map(|i| network.foward(i)).collect();
+
+        assert_eq!(batched, per_sample);
+    }
+}
+
+#[cfg(test)]
+mod save_load_tests {
+    use super::*;
+
+    #[test]
+    fn load_reproduces_saved_network_output() {
+        let mut network = ArtificialNetwork::new();
+        let mut layer = DenseLayer::<3, 2>::new();
+        layer.randomize();
+        network.add_layer(Box::new(layer));
+        network.add_layer(Box::new(DropoutLayer::new(2, 0.5)));
+
+        let input = vec![1.0, -2.0, 3.0];
+        let expected = network.foward(input.clone());
+
+        let path = std::env::temp_dir().join("axon_save_load_roundtrip_test.net");
+        let path = path.to_str().expect("temp path must be valid utf-8");
+        network.save(path).expect("save must succeed");
+        let loaded = ArtificialNetwork::load(path).expect("load must succeed");
+        let _ = fs::remove_file(path);
+
+        assert_eq!(loaded.foward(input), expected);
+    }
+
+    #[test]
+    fn load_reproduces_non_default_activation() {
+        let mut network = ArtificialNetwork::new();
+        let mut layer = DenseLayer::<3, 2>::new();
+        layer.randomize();
+        layer.set_activation(ActivationType::Softmax);
+        network.add_layer(Box::new(layer));
+
+        let input = vec![1.0, -2.0, 3.0];
+        let expected = network.foward(input.clone());
+
+        let path = std::env::temp_dir().join("axon_save_load_roundtrip_softmax_test.net");
+        let path = path.to_str().expect("temp path must be valid utf-8");
+        network.save(path).expect("save must succeed");
+        let loaded = ArtificialNetwork::load(path).expect("load must succeed");
+        let _ = fs::remove_file(path);
+
+        assert_eq!(loaded.foward(input), expected);
+    }
+}
+
+#[cfg(test)]
+mod cost_tests {
+    use super::*;
+
+    #[test]
+    fn squared_error_matches_documented_gradient() {
+        let cost = Cost::create(CostType::SquaredError);
+        assert_eq!((cost.f)(&[0.8], &[1.0]), (1.0_f64 - 0.8).powi(2));
+        assert_eq!((cost.d)(0.8, 1.0), 2.0 * (0.8 - 1.0));
+    }
+
+    #[test]
+    fn cross_entropy_matches_documented_gradient_and_clips_zero_output() {
+        let cost = Cost::create(CostType::CrossEntropy);
+        assert_eq!((cost.d)(0.8, 1.0), 0.8 - 1.0);
+
+        // output of exactly 0 would otherwise make ln(0) == -inf
+        let loss = (cost.f)(&[0.0], &[1.0]);
+        assert!(loss.is_finite());
+    }
+
+    #[test]
+    #[should_panic(expected = "Softmax")]
+    fn combined_cost_rejects_non_softmax_output_activation() {
+        let mut network = ArtificialNetwork::new();
+        let mut layer = DenseLayer::<2, 2>::new();
+        layer.randomize();
+        network.add_layer(Box::new(layer));
+        network.set_cost(CostType::CrossEntropy);
+
+        network.foward_mut(vec![0.1, 0.2]);
+        network.backward(vec![1.0, 0.0]);
+    }
+}
+
+#[cfg(test)]
+mod optimizer_tests {
+    use super::*;
+
+    #[test]
+    fn adam_second_step_matches_bias_corrected_formula() {
+        let mut layer = DenseLayer::<1, 1>::new();
+        layer.weights_mat[(0, 0)] = 0.5;
+        layer.m[(0, 0)] = 0.1;
+        layer.v[(0, 0)] = 0.01;
+        layer.grad_accum[(0, 0)] = 2.0;
+
+        layer.apply_gradients(1, 0.1, &Optimizer::adam(), 2);
+
+        // hand-computed: m=0.9*0.1+0.1*2=0.29, v=0.999*0.01+0.001*4=0.01399,
+        // m_hat=m/(1-0.9^2), v_hat=v/(1-0.999^2), w -= 0.1*m_hat/(sqrt(v_hat)+1e-8)
+        assert!((layer.weights_mat[(0, 0)] - 0.4423045008321315).abs() < 1e-9);
+    }
+
+    #[test]
+    fn sgd_step_is_plain_gradient_descent() {
+        let mut layer = DenseLayer::<1, 1>::new();
+        layer.weights_mat[(0, 0)] = 0.5;
+        layer.grad_accum[(0, 0)] = 2.0;
+
+        layer.apply_gradients(1, 0.1, &Optimizer::Sgd, 1);
+
+        assert!((layer.weights_mat[(0, 0)] - (0.5 - 0.1 * 2.0)).abs() < 1e-12);
+    }
+}
+
+#[cfg(test)]
+mod train_tests {
+    use super::*;
+    use std::cell::RefCell;
+    use std::rc::Rc;
+
+    #[test]
+    fn train_fires_callbacks_and_averages_epoch_loss_over_all_samples() {
+        let mut layer = DenseLayer::<2, 2>::new();
+        layer.randomize();
+
+        let mut network = ArtificialNetwork::new();
+        network.add_layer(Box::new(layer));
+
+        let errors = Rc::new(RefCell::new(Vec::new()));
+        let epochs_seen = Rc::new(RefCell::new(Vec::new()));
+
+        let errors_handle = Rc::clone(&errors);
+        network.on_error(move |loss| errors_handle.borrow_mut().push(loss));
+
+        let epochs_handle = Rc::clone(&epochs_seen);
+        network.on_epoch(move |epoch, loss| epochs_handle.borrow_mut().push((epoch, loss)));
+
+        let inputs = vec![
+            vec![1.0, 0.0],
+            vec![0.0, 1.0],
+            vec![1.0, 1.0],
+            vec![0.0, 0.0],
+        ];
+        let expected = inputs.clone();
+
+        // one on_error call per sample, one on_epoch call per epoch, batched
+        // over 2 batches of 2 samples each
+        let reported_loss = network.train(inputs, expected, 0.1, 1, 2, false);
+
+        assert_eq!(errors.borrow().len(), 4);
+        let manual_average: f64 = errors.borrow().iter().sum::<f64>() / 4.0;
+        assert!((reported_loss - manual_average).abs() < 1e-9);
+
+        assert_eq!(*epochs_seen.borrow(), vec![(0, reported_loss)]);
     }
-}
\ No newline at end of file
+}
+
+#[cfg(test)]
+mod dropout_regularization_tests {
+    use super::*;
+
+    #[test]
+    fn dropout_zeroes_all_units_when_keep_prob_is_zero() {
+        let mut layer = DropoutLayer::new(4, 0.0);
+        let out = layer.foward_mut(vec![1.0, 2.0, 3.0, 4.0]);
+        assert_eq!(out, vec![0.0; 4]);
+    }
+
+    #[test]
+    fn dropout_passes_survivors_through_unscaled_when_keep_prob_is_one() {
+        let mut layer = DropoutLayer::new(3, 1.0);
+        let out = layer.foward_mut(vec![1.0, 2.0, 3.0]);
+        assert_eq!(out, vec![1.0, 2.0, 3.0]);
+    }
+
+    #[test]
+    fn l2_decay_shrinks_weights_after_gradient_step() {
+        let mut layer = DenseLayer::<1, 1>::new();
+        layer.weights_mat[(0, 0)] = 1.0;
+        layer.set_l2(0.1);
+
+        // zero accumulated gradient isolates the L2 term: w -= l_rate * lambda * w
+        layer.apply_gradients(1, 0.1, &Optimizer::Sgd, 1);
+
+        assert!((layer.weights_mat[(0, 0)] - 0.99).abs() < 1e-12);
+    }
+
+    #[test]
+    fn max_norm_rescales_weight_row_to_the_configured_bound() {
+        let mut layer = DenseLayer::<2, 1>::new();
+        layer.weights_mat[(0, 0)] = 3.0;
+        layer.weights_mat[(0, 1)] = 4.0;
+        layer.set_max_norm(1.0);
+
+        layer.apply_gradients(1, 0.0, &Optimizer::Sgd, 1);
+
+        assert!((layer.weights_mat[(0, 0)] - 0.6).abs() < 1e-9);
+        assert!((layer.weights_mat[(0, 1)] - 0.8).abs() < 1e-9);
+    }
+}